@@ -19,6 +19,8 @@
  * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
  */
 
+use std::cell::RefCell;
+
 use glib::Cast;
 use regex::Regex;
 
@@ -40,8 +42,11 @@ use webkit2gtk_webextension::{
     DOMHTMLCollection,
     DOMHTMLCollectionExt,
     DOMHTMLElement,
+    DOMHTMLElementExt,
     DOMHTMLFieldSetElement,
     DOMHTMLFieldSetElementExtManual,
+    DOMHTMLIFrameElement,
+    DOMHTMLIFrameElementExt,
     DOMHTMLInputElement,
     DOMHTMLInputElementExt,
     DOMHTMLSelectElement,
@@ -53,10 +58,18 @@ use webkit2gtk_webextension::{
     DOMNodeExt,
     DOMNodeList,
     DOMNodeListExt,
+    DOMShadowRoot,
+    DOMShadowRootExt,
     WebPage,
     WebPageExt,
 };
 
+thread_local! {
+    /// The element last hovered via `mouse_over`, kept around so leaving it fires
+    /// `mouseout`/`mouseleave` and so mouse events can populate `relatedTarget`.
+    static LAST_HOVERED_ELEMENT: RefCell<Option<DOMElement>> = RefCell::new(None);
+}
+
 macro_rules! return_if_disabled {
     ($ty:ty, $element:expr) => {
         if $element.is::<$ty>() {
@@ -112,17 +125,57 @@ macro_rules! iter {
 iter!(NodeIter, DOMNodeList);
 iter!(ElementIter, DOMHTMLCollection);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Pos {
     pub x: f32,
     pub y: f32,
 }
 
+/// An element found while recursively walking a document, together with the coordinate
+/// offset of the frame it was found in (zero for the top document).
+#[derive(Debug)]
+pub struct FoundElement {
+    pub element: DOMElement,
+    pub frame_offset: Pos,
+}
+
 /// Trigger a click event on the element.
 pub fn click(element: &DOMElement, ctrl_key: bool) {
     mouse_event("click", element, ctrl_key);
 }
 
+/// Fire the full `mouseover` → `mousemove` → `mousedown` → `focus` → `mouseup` → `click`
+/// chain on the element, like a real pointer interaction, instead of the lone synthetic
+/// `click` event that `click()` dispatches, which some sites' handlers don't expect.
+pub fn click_sequence(element: &DOMElement, ctrl_key: bool) {
+    mouse_over(element);
+    mouse_event("mousemove", element, ctrl_key);
+    mouse_event("mousedown", element, ctrl_key);
+    focus_form_element(element);
+    mouse_event("mouseup", element, ctrl_key);
+    mouse_event("click", element, ctrl_key);
+}
+
+/// Focus the element if it is a form or editable element, the way a real browser does
+/// between mousedown and mouseup.
+fn focus_form_element(element: &DOMElement) {
+    let is_form_element =
+        element.is::<DOMHTMLButtonElement>() ||
+        element.is::<DOMHTMLInputElement>() ||
+        element.is::<DOMHTMLSelectElement>() ||
+        element.is::<DOMHTMLTextAreaElement>();
+    if is_form_element || is_editable(element) {
+        if let Ok(element) = element.clone().downcast::<DOMHTMLElement>() {
+            element.focus();
+        }
+    }
+}
+
+/// Get the element currently focused in the document, if any.
+pub fn get_active_element(document: &DOMDocument) -> Option<DOMElement> {
+    document.get_active_element()
+}
+
 /// Get the body element of the web page.
 pub fn get_body(page: &WebPage) -> Option<DOMHTMLElement> {
     page.get_dom_document().and_then(|document|
@@ -147,6 +200,58 @@ pub fn get_href(element: &DOMHTMLElement) -> Option<String> {
     }
 }
 
+/// What should happen when a link is activated.
+#[derive(Debug, PartialEq)]
+pub enum LinkAction {
+    /// Open the link in the current tab.
+    CurrentTab,
+    /// Open the link in a new tab, without switching to it.
+    NewBackgroundTab,
+    /// Open the link in a new tab and switch to it.
+    NewForegroundTab,
+    /// Open the link in a new window.
+    NewWindow,
+    /// Download the resource at the given (resolved) URL instead of navigating to it.
+    Download(String),
+}
+
+/// Decide how activating a link should open it, combining its `download` attribute,
+/// `target`/`rel` and href scheme with the held modifier keys.
+pub fn link_action(anchor: &DOMHTMLAnchorElement, ctrl_key: bool, shift_key: bool) -> LinkAction {
+    let element: DOMElement = anchor.clone().upcast();
+
+    if element.get_attribute("download").is_some() {
+        if let Some(href) = anchor.get_href() {
+            return LinkAction::Download(href);
+        }
+    }
+
+    let href = element.get_attribute("href").unwrap_or_default();
+    let href_lower = href.to_ascii_lowercase();
+    if href.starts_with('#') || href_lower.starts_with("mailto:") || href_lower.starts_with("javascript:") {
+        return LinkAction::CurrentTab;
+    }
+
+    if ctrl_key && shift_key {
+        return LinkAction::NewForegroundTab;
+    }
+    if ctrl_key {
+        return LinkAction::NewBackgroundTab;
+    }
+    if shift_key {
+        return LinkAction::NewWindow;
+    }
+
+    let has_new_tab_rel = anchor.get_rel()
+        .map(|rel| rel.split_whitespace().any(|token| token.eq_ignore_ascii_case("external")))
+        .unwrap_or(false);
+    if anchor.get_target().as_deref() == Some("_blank") || has_new_tab_rel {
+        return LinkAction::NewForegroundTab;
+    }
+
+    LinkAction::CurrentTab
+}
+
 /// Get the position of an element relative to the page root.
 pub fn get_position(element: &DOMElement) -> Option<Pos> {
     let rects = element.get_client_rects()?;
@@ -162,12 +267,43 @@ pub fn get_position(element: &DOMElement) -> Option<Pos> {
     })
 }
 
+/// Programmatically remove focus from an element, e.g. to leave insert mode.
+pub fn blur(element: &DOMElement) {
+    if let Ok(element) = element.clone().downcast::<DOMHTMLElement>() {
+        element.blur();
+    }
+}
+
 /// Hide an element.
 pub fn hide(element: &DOMElement) {
     let style = wtry_opt_no_ret!(element.get_style());
     wtry!(style.set_property("display", "none", ""));
 }
 
+/// Check if an element is editable: a text input, a `textarea`, or an element with
+/// `contenteditable` set, inherited from an ancestor.
+pub fn is_editable(element: &DOMElement) -> bool {
+    if element.is::<DOMHTMLInputElement>() && is_text_input(element) {
+        return true;
+    }
+    if element.is::<DOMHTMLTextAreaElement>() {
+        return true;
+    }
+
+    let mut element = Some(element.clone());
+    while let Some(el) = element {
+        if let Ok(html_element) = el.clone().downcast::<DOMHTMLElement>() {
+            match html_element.get_content_editable().as_deref() {
+                Some("true") => return true,
+                Some("false") => return false,
+                _ => (),
+            }
+        }
+        element = el.get_parent_element();
+    }
+    false
+}
+
 /// Check if an input element is enabled.
 /// Other element types return true.
 pub fn is_enabled(element: &DOMElement) -> bool {
@@ -241,6 +377,44 @@ pub fn is_visible(document: &DOMDocument, element: &DOMElement) -> bool {
     (x1 >= 0.0 || x2 >= 0.0) && x1 < width && (y1 >= 0.0 || y2 >= 0.0) && y1 < height
 }
 
+/// Check if an element is visible, in the viewport, and not occluded by another element sitting
+/// on top of it (a sticky header, modal overlay, cookie banner, …).
+pub fn is_clickable(document: &DOMDocument, element: &DOMElement) -> bool {
+    is_visible(document, element) && !is_occluded(document, element)
+}
+
+/// Check whether another, unrelated element is the top-most one at `element`'s own center
+/// point, which would make it unclickable even though it is on-screen.
+fn is_occluded(document: &DOMDocument, element: &DOMElement) -> bool {
+    let rect = unwrap_opt_or_ret!(element.get_bounding_client_rect(), true);
+    let cx = (rect.get_left() + rect.get_width() / 2.0) as f64;
+    let cy = (rect.get_top() + rect.get_height() / 2.0) as f64;
+    let hit_element = unwrap_opt_or_ret!(document.element_from_point(cx, cy), true);
+
+    let mut node = Some(hit_element);
+    while let Some(el) = node {
+        if &el == element {
+            return false;
+        }
+        node = get_parent_or_shadow_host(&el);
+    }
+    true
+}
+
+/// Get `element`'s parent, crossing out of an open shadow root into its host element when
+/// `element` is one of the shadow root's direct children. A plain `get_parent_element` call
+/// stops there because a `ShadowRoot` is not itself a `DOMElement`, which would otherwise make
+/// `is_occluded` treat elements found inside shadow DOM (via `collect_elements`) as occluded by
+/// their own shadow-internal content.
+fn get_parent_or_shadow_host(element: &DOMElement) -> Option<DOMElement> {
+    if let Some(parent) = element.get_parent_element() {
+        return Some(parent);
+    }
+    element.get_parent_node()
+        .and_then(|node| node.downcast::<DOMShadowRoot>().ok())
+        .and_then(|shadow_root| shadow_root.get_host())
+}
+
 /// Trigger a mouse down event on the element.
 pub fn mouse_down(element: &DOMElement) {
     mouse_event("mousedown", element, false);
@@ -253,14 +427,55 @@ pub fn mouse_enter(element: &DOMElement) {
 }*/
 
 /// Trigger a mouse event on the element.
+///
+/// When hovering a new element (`mouseover`), this first fires `mouseout`/`mouseleave` on the
+/// previously hovered element, so the event chain matches what a real pointer move produces.
 pub fn mouse_event(event_name: &str, element: &DOMElement, ctrl_key: bool) {
+    if event_name == "mouseover" {
+        let previous = LAST_HOVERED_ELEMENT.with(|last| last.borrow_mut().replace(element.clone()));
+        let previous = previous
+            .filter(|previous| previous != element)
+            // A stale reference from a page that has since been navigated away from: its
+            // document (and possibly the node itself) may no longer be alive, so drop it
+            // instead of dispatching on it.
+            .filter(|previous| previous.get_owner_document() == element.get_owner_document());
+        if let Some(ref previous) = previous {
+            dispatch_mouse_event("mouseout", previous, false, element);
+            dispatch_mouse_event("mouseleave", previous, false, element);
+        }
+        // The previously hovered element (or itself, on the very first hover) is the
+        // `relatedTarget` of the `mouseover` event itself.
+        let related_target = previous.unwrap_or_else(|| element.clone());
+        dispatch_mouse_event("mouseover", element, ctrl_key, &related_target);
+        return;
+    }
+
+    // Only the other hover-pair events care about the last hovered element; every other event
+    // (click, mousedown, mouseup, mousemove, …) keeps the plain self-reference it always had.
+    let related_target = match event_name {
+        "mouseout" | "mouseenter" | "mouseleave" => {
+            LAST_HOVERED_ELEMENT.with(|last| last.borrow().clone())
+                .filter(|last| last.get_owner_document() == element.get_owner_document())
+                .unwrap_or_else(|| element.clone())
+        },
+        _ => element.clone(),
+    };
+    dispatch_mouse_event(event_name, element, ctrl_key, &related_target);
+}
+
+/// Dispatch a `MouseEvents` event on `element`, using `related_target` as the event's
+/// `relatedTarget` (e.g. the element being left or entered).
+fn dispatch_mouse_event(event_name: &str, element: &DOMElement, ctrl_key: bool, related_target: &DOMElement) {
     let event = wtry_opt_no_ret!(element.get_owner_document()
         .and_then(|document| document.create_event("MouseEvents").ok()));
     let window = wtry_opt_no_ret!(element.get_owner_document()
         .and_then(|document| document.get_default_view()));
     let event = wtry_no_show!(event.downcast::<DOMMouseEvent>());
-    // TODO: use the previously hovered element for the last parameter.
-    event.init_mouse_event(event_name, true, true, &window, 0, 0, 0, 0, 0, ctrl_key, false, false, false, 0, element);
+    let detail = match event_name {
+        "click" | "dblclick" | "mousedown" | "mouseup" => 1,
+        _ => 0,
+    };
+    event.init_mouse_event(event_name, true, true, &window, detail, 0, 0, 0, 0, ctrl_key, false, false, false, 0, related_target);
     let element: DOMEventTarget = element.clone().upcast();
     wtry!(element.dispatch_event(&event));
 }
@@ -283,15 +498,62 @@ pub fn show(element: &DOMElement) {
 
 /// Lookup dom elements by tag and regex
 pub fn match_pattern(document: &DOMDocument, selector: &str, regex: Regex) -> Option<DOMElement> {
-    let iter = NodeIter::new(document.get_elements_by_tag_name(selector));
-
-    for element in iter {
-        if let Some(text) = element.get_inner_html() {
+    for found in collect_elements(document, selector) {
+        if let Some(text) = found.element.get_inner_html() {
             if regex.is_match(&text) {
-                return Some(element);
+                return Some(found.element);
             }
         }
     }
 
     None
 }
+
+/// Recursively collect all elements matching the tag name `selector`, descending into open
+/// shadow roots and same-origin iframes (each iframe contributing its own coordinate offset;
+/// elements in a shadow root share the offset of the document hosting it).
+pub fn collect_elements(document: &DOMDocument, selector: &str) -> Vec<FoundElement> {
+    let mut elements = vec![];
+    collect_document_elements(document, selector, Pos { x: 0.0, y: 0.0 }, &mut elements);
+    elements
+}
+
+/// Collect `selector`'s matches in `document`, then recurse into any shadow roots and iframes.
+fn collect_document_elements(document: &DOMDocument, selector: &str, offset: Pos, elements: &mut Vec<FoundElement>) {
+    collect_elements_in(
+        document.get_elements_by_tag_name(selector),
+        document.get_elements_by_tag_name("*"),
+        selector,
+        offset,
+        elements,
+    );
+}
+
+fn collect_elements_in(matches: Option<DOMNodeList>, all: Option<DOMNodeList>, selector: &str, offset: Pos, elements: &mut Vec<FoundElement>) {
+    for element in NodeIter::new(matches) {
+        elements.push(FoundElement { element, frame_offset: offset });
+    }
+
+    for element in NodeIter::new(all) {
+        if let Some(shadow_root) = element.get_shadow_root() {
+            collect_elements_in(
+                shadow_root.get_elements_by_tag_name(selector),
+                shadow_root.get_elements_by_tag_name("*"),
+                selector,
+                offset,
+                elements,
+            );
+        }
+
+        if let Ok(iframe) = element.clone().downcast::<DOMHTMLIFrameElement>() {
+            // A cross-origin iframe's content document is inaccessible; skip it rather than
+            // trying to walk a frame we have no visibility into.
+            if let Some(content_document) = iframe.get_content_document() {
+                let frame_offset = get_position(&element)
+                    .map(|pos| Pos { x: offset.x + pos.x, y: offset.y + pos.y })
+                    .unwrap_or(offset);
+                collect_document_elements(&content_document, selector, frame_offset, elements);
+            }
+        }
+    }
+}